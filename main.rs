@@ -1,300 +1,1588 @@
-use num_complex::Complex64;
-use std::collections::HashMap;
-use std::io::{self, Write};
-
-struct Circuit {
-    num_qubits: usize,
-    gates: Vec<(Gate, Vec<usize>)>,
-}
-
-impl Circuit {
-    fn new(num_qubits: usize) -> Self {
-        Circuit {
-            num_qubits,
-            gates: Vec::new(),
-        }
-    }
-
-    fn add_gate(&mut self, gate: Gate, targets: Vec<usize>) {
-        println!("Adding gate {:?} on qubits {:?}", gate, targets);
-        self.gates.push((gate, targets));
-    }
-
-    fn run(&self) -> Vec<Complex64> {
-        let mut state = vec![Complex64::new(0.0, 0.0); 1 << self.num_qubits];
-        state[0] = Complex64::new(1.0, 0.0);
-        for (gate, targets) in &self.gates {
-            println!("Applying {:?} on {:?}", gate, targets);
-            state = gate.apply(&state, targets.clone(), self.num_qubits);
-        }
-        state
-    }
-
-    fn compute_probabilities(&self, state: &[Complex64]) -> Vec<f64> {
-        state.iter().map(|amp| amp.norm_sqr()).collect()
-    }
-}
-
-#[derive(Clone, Debug)]
-enum Gate {
-    H,
-    T,
-    X,
-    Y,
-    Z,
-    ID,
-    CNOT,
-    SWAP,
-}
-
-impl Gate {
-    fn apply(&self, state: &[Complex64], targets: Vec<usize>, num_qubits: usize) -> Vec<Complex64> {
-        match self {
-            Gate::H => apply_single_qubit_gate(state, hadamard(), targets[0], num_qubits),
-            Gate::T => apply_single_qubit_gate(state, t_gate(), targets[0], num_qubits),
-            Gate::X => apply_single_qubit_gate(state, pauli_x(), targets[0], num_qubits),
-            Gate::Y => apply_single_qubit_gate(state, pauli_y(), targets[0], num_qubits),
-            Gate::Z => apply_single_qubit_gate(state, pauli_z(), targets[0], num_qubits),
-            Gate::ID => apply_single_qubit_gate(state, identity(), targets[0], num_qubits),
-            Gate::CNOT => apply_two_qubit_gate(state, cnot(), targets, num_qubits),
-            Gate::SWAP => apply_two_qubit_gate(state, swap(), targets, num_qubits),
-        }
-    }
-}
-
-fn hadamard() -> [[Complex64; 2]; 2] {
-    let scale = 1.0 / (2.0_f64).sqrt();
-    [
-        [Complex64::new(scale, 0.0), Complex64::new(scale, 0.0)],
-        [Complex64::new(scale, 0.0), Complex64::new(-scale, 0.0)],
-    ]
-}
-
-fn t_gate() -> [[Complex64; 2]; 2] {
-    [
-        [Complex64::new(1.0, 0.0), Complex64::new(0.0, 0.0)],
-        [Complex64::new(0.0, 0.0), Complex64::new(0.7071, 0.7071)],
-    ]
-}
-
-fn pauli_x() -> [[Complex64; 2]; 2] {
-    [
-        [Complex64::new(0.0, 0.0), Complex64::new(1.0, 0.0)],
-        [Complex64::new(1.0, 0.0), Complex64::new(0.0, 0.0)],
-    ]
-}
-
-fn pauli_y() -> [[Complex64; 2]; 2] {
-    [
-        [Complex64::new(0.0, 0.0), Complex64::new(0.0, -1.0)],
-        [Complex64::new(0.0, 1.0), Complex64::new(0.0, 0.0)],
-    ]
-}
-
-fn pauli_z() -> [[Complex64; 2]; 2] {
-    [
-        [Complex64::new(1.0, 0.0), Complex64::new(0.0, 0.0)],
-        [Complex64::new(0.0, 0.0), Complex64::new(-1.0, 0.0)],
-    ]
-}
-
-fn identity() -> [[Complex64; 2]; 2] {
-    [
-        [Complex64::new(1.0, 0.0), Complex64::new(0.0, 0.0)],
-        [Complex64::new(0.0, 0.0), Complex64::new(1.0, 0.0)],
-    ]
-}
-
-fn cnot() -> [[Complex64; 4]; 4] {
-    [
-        [
-            Complex64::new(1.0, 0.0),
-            Complex64::new(0.0, 0.0),
-            Complex64::new(0.0, 0.0),
-            Complex64::new(0.0, 0.0),
-        ],
-        [
-            Complex64::new(0.0, 0.0),
-            Complex64::new(1.0, 0.0),
-            Complex64::new(0.0, 0.0),
-            Complex64::new(0.0, 0.0),
-        ],
-        [
-            Complex64::new(0.0, 0.0),
-            Complex64::new(0.0, 0.0),
-            Complex64::new(0.0, 0.0),
-            Complex64::new(1.0, 0.0),
-        ],
-        [
-            Complex64::new(0.0, 0.0),
-            Complex64::new(0.0, 0.0),
-            Complex64::new(1.0, 0.0),
-            Complex64::new(0.0, 0.0),
-        ],
-    ]
-}
-
-fn swap() -> [[Complex64; 4]; 4] {
-    [
-        [
-            Complex64::new(1.0, 0.0),
-            Complex64::new(0.0, 0.0),
-            Complex64::new(0.0, 0.0),
-            Complex64::new(0.0, 0.0),
-        ],
-        [
-            Complex64::new(0.0, 0.0),
-            Complex64::new(0.0, 0.0),
-            Complex64::new(1.0, 0.0),
-            Complex64::new(0.0, 0.0),
-        ],
-        [
-            Complex64::new(0.0, 0.0),
-            Complex64::new(1.0, 0.0),
-            Complex64::new(0.0, 0.0),
-            Complex64::new(0.0, 0.0),
-        ],
-        [
-            Complex64::new(0.0, 0.0),
-            Complex64::new(0.0, 0.0),
-            Complex64::new(0.0, 0.0),
-            Complex64::new(1.0, 0.0),
-        ],
-    ]
-}
-
-fn apply_single_qubit_gate(
-    state: &[Complex64],
-    gate: [[Complex64; 2]; 2],
-    target: usize,
-    num_qubits: usize,
-) -> Vec<Complex64> {
-    let dim = 1 << num_qubits;
-    let mut new_state = vec![Complex64::new(0.0, 0.0); dim];
-
-    for i in 0..dim {
-        let target_bit = (i >> target) & 1;
-        for j in 0..2 {
-            let source = (i & !(1 << target)) | (j << target);
-            new_state[i] += gate[target_bit][j] * state[source];
-        }
-    }
-
-    new_state
-}
-
-fn apply_two_qubit_gate(
-    state: &[Complex64],
-    gate: [[Complex64; 4]; 4],
-    targets: Vec<usize>,
-    num_qubits: usize,
-) -> Vec<Complex64> {
-    let dim = 1 << num_qubits;
-    let mut new_state = vec![Complex64::new(0.0, 0.0); dim];
-
-    let control = targets[0];
-    let target = targets[1];
-
-    for i in 0..dim {
-        let control_bit = (i >> control) & 1;
-        let target_bit = (i >> target) & 1;
-        let index = (control_bit << 1) | target_bit;
-
-        for j in 0..4 {
-            let source = (i & !(1 << control) & !(1 << target))
-                | ((j >> 1) << control)
-                | ((j & 1) << target);
-            new_state[i] += gate[index][j] * state[source];
-        }
-    }
-
-    new_state
-}
-
-fn main() {
-    let mut input = String::new();
-    print!("Enter number of qubits: ");
-    io::stdout().flush().unwrap();
-    input.clear();
-    io::stdin()
-        .read_line(&mut input)
-        .expect("Failed to read line");
-    let num_qubits: usize = input.trim().parse().expect("Invalid number");
-
-    let mut circuit = Circuit::new(num_qubits);
-
-    let gate_map: HashMap<&str, Gate> = [
-        ("h", Gate::H),
-        ("t", Gate::T),
-        ("x", Gate::X),
-        ("y", Gate::Y),
-        ("z", Gate::Z),
-        ("id", Gate::ID),
-        ("cnot", Gate::CNOT),
-        ("swap", Gate::SWAP),
-    ]
-    .iter()
-    .map(|&(k, ref v)| (k, v.clone()))
-    .collect();
-
-    print!("Enter number of instructions: ");
-    io::stdout().flush().unwrap();
-    input.clear();
-    io::stdin()
-        .read_line(&mut input)
-        .expect("Failed to read line");
-    let num_instructions: usize = input.trim().parse().expect("Invalid number");
-
-    for _ in 0..num_instructions {
-        input.clear();
-        print!("Enter instruction (e.g. 'h q[0]' or 'x q[1]'): ");
-        io::stdout().flush().unwrap();
-        io::stdin()
-            .read_line(&mut input)
-            .expect("Failed to read line");
-        let instruction = input.trim();
-
-        let parts: Vec<&str> = instruction.split_whitespace().collect();
-        if parts.len() < 2 {
-            println!("Invalid instruction: {}", instruction);
-            continue;
-        }
-
-        let gate_name = parts[0];
-        let target_str = parts[1];
-
-        let gate = match gate_map.get(gate_name) {
-            Some(g) => g.clone(),
-            None => {
-                println!("Unknown gate: {}", gate_name);
-                continue;
-            }
-        };
-
-        let targets: Vec<usize> = target_str
-            .split(&['[', ']', ','])
-            .filter_map(|s| s.parse::<usize>().ok())
-            .collect();
-
-        if targets.is_empty() || targets.iter().any(|&q| q >= num_qubits) {
-            println!("Invalid target qubits for instruction: {}", instruction);
-            continue;
-        }
-
-        circuit.add_gate(gate, targets);
-    }
-
-    println!("Starting circuit execution...");
-    let final_state = circuit.run();
-
-    let probabilities = circuit.compute_probabilities(&final_state);
-    println!("Final probabilities:");
-    for (state, prob) in probabilities.iter().enumerate() {
-        println!(
-            "State |{:0width$b}>: {:.5}",
-            state,
-            prob,
-            width = num_qubits
-        );
-    }
-}
+use num_complex::Complex64;
+use rand::Rng;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// State-vector size (in amplitudes) at or above which gate application switches
+/// from a serial loop to a rayon `par_iter_mut()`, avoiding thread overhead
+/// on small circuits.
+const DEFAULT_PARALLEL_THRESHOLD: usize = 1 << 14;
+
+struct Circuit {
+    num_qubits: usize,
+    num_clbits: usize,
+    gates: Vec<CircuitOp>,
+    parallel_threshold: usize,
+}
+
+impl Circuit {
+    fn new(num_qubits: usize) -> Self {
+        Circuit {
+            num_qubits,
+            num_clbits: num_qubits,
+            gates: Vec::new(),
+            parallel_threshold: DEFAULT_PARALLEL_THRESHOLD,
+        }
+    }
+
+    /// Set the state-vector size at or above which gate application runs on
+    /// rayon's thread pool instead of a single thread.
+    fn set_parallel_threshold(&mut self, threshold: usize) {
+        self.parallel_threshold = threshold;
+    }
+
+    fn add_gate(&mut self, gate: Gate, targets: Vec<usize>) {
+        println!("Adding gate {:?} on qubits {:?}", gate, targets);
+        self.gates.push(CircuitOp::Gate(gate, targets));
+    }
+
+    /// Record a measurement of `qubit` in the given `basis`, storing the
+    /// 0/1 outcome in classical bit `classical_bit` when the circuit runs.
+    fn measure(&mut self, qubit: usize, basis: Basis, classical_bit: usize) {
+        println!(
+            "Adding measurement of qubit {} in {:?} basis -> c[{}]",
+            qubit, basis, classical_bit
+        );
+        self.gates.push(CircuitOp::Measure(qubit, basis, classical_bit));
+    }
+
+    /// Measure every qubit in the given basis, qubit `i` landing in classical bit `i`.
+    fn measure_all(&mut self, basis: Basis) {
+        println!("Adding measure-all in {:?} basis", basis);
+        self.gates.push(CircuitOp::MeasureAll(basis));
+    }
+
+    /// Non-destructively record the probability of `qubit` reading 0 in the
+    /// given basis, without collapsing the state. Unlike `measure`, this
+    /// never writes a 0/1 outcome into the classical register: a peek is a
+    /// probability, not a (pseudo-)random bit, so it gets its own `peek_slot`
+    /// in a separate float-valued register instead of aliasing `Measure`'s
+    /// storage.
+    fn peek(&mut self, qubit: usize, basis: Basis, peek_slot: usize) {
+        println!(
+            "Adding peek of qubit {} in {:?} basis -> p[{}]",
+            qubit, basis, peek_slot
+        );
+        self.gates.push(CircuitOp::Peek(qubit, basis, peek_slot));
+    }
+
+    /// Measure `qubit` in the Z basis and flip it back to |0> if it read 1,
+    /// e.g. to recycle a qubit mid-circuit (quantum teleportation, feed-forward).
+    fn reset(&mut self, qubit: usize) {
+        println!("Adding reset of qubit {}", qubit);
+        self.gates.push(CircuitOp::Reset(qubit));
+    }
+
+    /// Reset every qubit, re-initializing the whole register to |00...0>.
+    fn reset_all(&mut self) {
+        println!("Adding reset-all");
+        self.gates.push(CircuitOp::ResetAll);
+    }
+
+    /// Apply `gate` to `targets` only if the classical bits named by
+    /// `classical_bits` (bit `i` of `classical_bits` contributing `2^i`)
+    /// currently assemble to `value`.
+    fn conditional_gate(
+        &mut self,
+        classical_bits: Vec<usize>,
+        value: u64,
+        gate: Gate,
+        targets: Vec<usize>,
+    ) {
+        println!(
+            "Adding conditional gate {:?} on {:?} if c{:?} == {}",
+            gate, targets, classical_bits, value
+        );
+        self.gates.push(CircuitOp::ConditionalGate {
+            classical_bits,
+            value,
+            gate,
+            targets,
+        });
+    }
+
+    /// Execute every op in order, returning the final state, the classical
+    /// register populated by any `Measure`/`MeasureAll` ops, and the
+    /// float-valued peek register populated by any `Peek` ops. The peek
+    /// register is kept separate from the classical one so a peeked
+    /// probability can never be read back by a `ConditionalGate` as if it
+    /// were a real measurement outcome.
+    fn run_with_rng(&self, rng: &mut impl Rng) -> (Vec<Complex64>, Vec<u8>, HashMap<usize, f64>) {
+        let mut state = vec![Complex64::new(0.0, 0.0); 1 << self.num_qubits];
+        state[0] = Complex64::new(1.0, 0.0);
+        let mut classical = vec![0u8; self.num_clbits];
+        let mut peeks: HashMap<usize, f64> = HashMap::new();
+
+        for op in &self.gates {
+            match op {
+                CircuitOp::Gate(gate, targets) => {
+                    println!("Applying {:?} on {:?}", gate, targets);
+                    state = gate.apply(&state, targets.clone(), self.num_qubits, self.parallel_threshold);
+                }
+                CircuitOp::Measure(qubit, basis, classical_bit) => {
+                    let outcome = measure_qubit(
+                        &mut state,
+                        *qubit,
+                        *basis,
+                        self.num_qubits,
+                        rng,
+                        self.parallel_threshold,
+                    );
+                    println!("Measured qubit {} in {:?} basis -> {}", qubit, basis, outcome);
+                    classical[*classical_bit] = outcome;
+                }
+                CircuitOp::MeasureAll(basis) => {
+                    for qubit in 0..self.num_qubits {
+                        let outcome = measure_qubit(
+                            &mut state,
+                            qubit,
+                            *basis,
+                            self.num_qubits,
+                            rng,
+                            self.parallel_threshold,
+                        );
+                        println!("Measured qubit {} in {:?} basis -> {}", qubit, basis, outcome);
+                        classical[qubit] = outcome;
+                    }
+                }
+                CircuitOp::Peek(qubit, basis, peek_slot) => {
+                    let prob0 =
+                        peek_qubit(&state, *qubit, *basis, self.num_qubits, self.parallel_threshold);
+                    println!(
+                        "Peek qubit {} in {:?} basis -> P(0) = {:.5}",
+                        qubit, basis, prob0
+                    );
+                    peeks.insert(*peek_slot, prob0);
+                }
+                CircuitOp::Reset(qubit) => {
+                    let outcome = measure_qubit(
+                        &mut state,
+                        *qubit,
+                        Basis::Z,
+                        self.num_qubits,
+                        rng,
+                        self.parallel_threshold,
+                    );
+                    if outcome == 1 {
+                        state = apply_single_qubit_gate(
+                            &state,
+                            pauli_x(),
+                            *qubit,
+                            self.num_qubits,
+                            self.parallel_threshold,
+                        );
+                    }
+                }
+                CircuitOp::ResetAll => {
+                    state = vec![Complex64::new(0.0, 0.0); 1 << self.num_qubits];
+                    state[0] = Complex64::new(1.0, 0.0);
+                }
+                CircuitOp::ConditionalGate {
+                    classical_bits,
+                    value,
+                    gate,
+                    targets,
+                } => {
+                    let assembled = classical_bits
+                        .iter()
+                        .enumerate()
+                        .fold(0u64, |acc, (i, &bit)| acc | ((classical[bit] as u64) << i));
+                    if assembled == *value {
+                        println!(
+                            "Condition c{:?} == {} met, applying {:?} on {:?}",
+                            classical_bits, value, gate, targets
+                        );
+                        state = gate.apply(&state, targets.clone(), self.num_qubits, self.parallel_threshold);
+                    } else {
+                        println!(
+                            "Condition c{:?} == {} not met ({}), skipping {:?} on {:?}",
+                            classical_bits, value, assembled, gate, targets
+                        );
+                    }
+                }
+            }
+        }
+
+        (state, classical, peeks)
+    }
+
+    fn compute_probabilities(&self, state: &[Complex64]) -> Vec<f64> {
+        state.iter().map(|amp| amp.norm_sqr()).collect()
+    }
+
+    /// Run the circuit `shots` times and return a histogram of observed
+    /// classical bitstrings, e.g. `{"00": 480, "11": 520}`.
+    ///
+    /// When the circuit contains only unitary gates (no measurement, peek,
+    /// reset or conditional op), this samples directly from the final
+    /// probability distribution instead of rerunning the circuit for every
+    /// shot; any other op, even a final measurement, makes every shot a
+    /// full run.
+    fn sample(&self, shots: usize) -> HashMap<String, usize> {
+        let mut rng = rand::thread_rng();
+        let mut counts: HashMap<String, usize> = HashMap::new();
+
+        let has_measurement = self
+            .gates
+            .iter()
+            .any(|op| !matches!(op, CircuitOp::Gate(_, _)));
+
+        if has_measurement {
+            for _ in 0..shots {
+                let (_, classical, _) = self.run_with_rng(&mut rng);
+                let bitstring: String = classical
+                    .iter()
+                    .map(|bit| if *bit == 1 { '1' } else { '0' })
+                    .collect();
+                *counts.entry(bitstring).or_insert(0) += 1;
+            }
+        } else {
+            let final_state = self.run_with_rng(&mut rng).0;
+            let probabilities = self.compute_probabilities(&final_state);
+            for _ in 0..shots {
+                let r: f64 = rng.gen();
+                let mut acc = 0.0;
+                let mut outcome = probabilities.len() - 1;
+                for (i, p) in probabilities.iter().enumerate() {
+                    acc += p;
+                    if r < acc {
+                        outcome = i;
+                        break;
+                    }
+                }
+                let bitstring = format!("{:0width$b}", outcome, width = self.num_qubits);
+                *counts.entry(bitstring).or_insert(0) += 1;
+            }
+        }
+
+        counts
+    }
+
+    /// Serialize this circuit to OpenQASM 2.0 source, one line per op
+    /// (`MeasureAll` and `ResetAll` expand to one line per qubit).
+    fn to_openqasm(&self) -> String {
+        let mut out = String::new();
+        out.push_str("OPENQASM 2.0;\n");
+        out.push_str("include \"qelib1.inc\";\n");
+        out.push_str(&format!("qreg q[{}];\n", self.num_qubits));
+        if self.num_clbits > 0 {
+            out.push_str(&format!("creg c[{}];\n", self.num_clbits));
+        }
+
+        for op in &self.gates {
+            match op {
+                CircuitOp::Gate(gate, targets) => {
+                    out.push_str(&gate_to_qasm(gate, targets));
+                    out.push('\n');
+                }
+                CircuitOp::Measure(qubit, _basis, classical_bit) => {
+                    out.push_str(&format!("measure q[{}] -> c[{}];\n", qubit, classical_bit));
+                }
+                CircuitOp::MeasureAll(_basis) => {
+                    for qubit in 0..self.num_qubits {
+                        out.push_str(&format!("measure q[{}] -> c[{}];\n", qubit, qubit));
+                    }
+                }
+                CircuitOp::Peek(qubit, basis, _peek_slot) => {
+                    out.push_str(&format!(
+                        "// peek q[{}] in {:?} basis (not representable in OpenQASM 2.0)\n",
+                        qubit, basis
+                    ));
+                }
+                CircuitOp::Reset(qubit) => {
+                    out.push_str(&format!("reset q[{}];\n", qubit));
+                }
+                CircuitOp::ResetAll => {
+                    for qubit in 0..self.num_qubits {
+                        out.push_str(&format!("reset q[{}];\n", qubit));
+                    }
+                }
+                CircuitOp::ConditionalGate {
+                    classical_bits,
+                    value,
+                    gate,
+                    targets,
+                } => {
+                    // OpenQASM 2.0's `if` only conditions on a whole creg
+                    // matching a value, not an arbitrary subset of bits. The
+                    // full 0..num_clbits register (the only shape this
+                    // codebase ever produces, via parse_if_statement and the
+                    // interactive `if(...)` form) maps onto it directly; any
+                    // other subset isn't representable and falls back to a
+                    // comment.
+                    let full_register: Vec<usize> = (0..self.num_clbits).collect();
+                    if *classical_bits == full_register {
+                        out.push_str(&format!("if(c=={}) {}\n", value, gate_to_qasm(gate, targets)));
+                    } else {
+                        out.push_str(&format!(
+                            "// conditional: if c{:?} == {} then {}\n",
+                            classical_bits,
+                            value,
+                            gate_to_qasm(gate, targets)
+                        ));
+                    }
+                }
+            }
+        }
+
+        out
+    }
+
+    /// Parse OpenQASM 2.0 source into a `Circuit`. Only the subset of the
+    /// language this simulator can execute is supported: `qreg`/`creg`
+    /// declarations, the gates in `gate_from_qasm`, `measure`, `reset`, and
+    /// whole-register `if(c==value) gate ...;` conditionals.
+    fn from_openqasm(src: &str) -> Result<Circuit, String> {
+        let mut circuit: Option<Circuit> = None;
+
+        for raw_statement in src.split(';') {
+            let stripped = strip_qasm_comment(raw_statement);
+            let statement = stripped.trim();
+            if statement.is_empty()
+                || statement.starts_with("OPENQASM")
+                || statement.starts_with("include")
+                || statement.starts_with("barrier")
+            {
+                continue;
+            }
+
+            if let Some(rest) = statement.strip_prefix("qreg") {
+                let num_qubits = parse_register_size(rest, "q")?;
+                circuit = Some(Circuit::new(num_qubits));
+                continue;
+            }
+
+            if let Some(rest) = statement.strip_prefix("creg") {
+                let num_clbits = parse_register_size(rest, "c")?;
+                let circuit = circuit
+                    .as_mut()
+                    .ok_or_else(|| "creg declared before qreg".to_string())?;
+                circuit.num_clbits = num_clbits;
+                continue;
+            }
+
+            let circuit = circuit
+                .as_mut()
+                .ok_or_else(|| format!("gate statement before qreg declaration: {}", statement))?;
+
+            if let Some(rest) = statement.strip_prefix("measure") {
+                let (qubit, classical_bit) = parse_measure(rest)?;
+                check_qubit_index(qubit, circuit.num_qubits)?;
+                check_clbit_index(classical_bit, circuit.num_clbits)?;
+                circuit.measure(qubit, Basis::Z, classical_bit);
+                continue;
+            }
+
+            if let Some(rest) = statement.strip_prefix("reset") {
+                let qubit = *parse_qubit_list(rest.trim())?
+                    .first()
+                    .ok_or_else(|| "malformed reset statement: missing qubit".to_string())?;
+                check_qubit_index(qubit, circuit.num_qubits)?;
+                circuit.reset(qubit);
+                continue;
+            }
+
+            if let Some(rest) = statement.strip_prefix("if") {
+                let (classical_bits, value, gate_stmt) = parse_if_statement(rest, circuit.num_clbits)?;
+                let gate_parts: Vec<&str> = gate_stmt.split_whitespace().collect();
+                if gate_parts.len() < 2 {
+                    return Err(format!("malformed conditional gate statement: {}", statement));
+                }
+                let (name, params) = split_gate_params(gate_parts[0])?;
+                let qubits = parse_qubit_list(gate_parts[1])?;
+                let (gate, targets) = gate_from_qasm(name, &params, &qubits, circuit.num_qubits)?;
+                circuit.conditional_gate(classical_bits, value, gate, targets);
+                continue;
+            }
+
+            let parts: Vec<&str> = statement.split_whitespace().collect();
+            if parts.len() < 2 {
+                return Err(format!("malformed gate statement: {}", statement));
+            }
+
+            let (name, params) = split_gate_params(parts[0])?;
+            let qubits = parse_qubit_list(parts[1])?;
+            let (gate, targets) = gate_from_qasm(name, &params, &qubits, circuit.num_qubits)?;
+            circuit.add_gate(gate, targets);
+        }
+
+        circuit.ok_or_else(|| "missing qreg declaration".to_string())
+    }
+}
+
+/// Measurement basis for `Circuit::measure`/`measure_all`/`peek`.
+#[derive(Clone, Copy, Debug)]
+enum Basis {
+    X,
+    Y,
+    Z,
+}
+
+/// A single entry in a circuit's instruction list: either a unitary `Gate`
+/// applied to some targets, or a measurement/reset/classically-conditioned op.
+#[derive(Clone, Debug)]
+enum CircuitOp {
+    Gate(Gate, Vec<usize>),
+    Measure(usize, Basis, usize),
+    MeasureAll(Basis),
+    Peek(usize, Basis, usize),
+    Reset(usize),
+    ResetAll,
+    ConditionalGate {
+        classical_bits: Vec<usize>,
+        value: u64,
+        gate: Gate,
+        targets: Vec<usize>,
+    },
+}
+
+#[derive(Clone, Debug)]
+enum Gate {
+    H,
+    T,
+    X,
+    Y,
+    Z,
+    ID,
+    CNOT,
+    SWAP,
+    RX(f64),
+    RY(f64),
+    RZ(f64),
+    S,
+    Sdg,
+    V,
+    U1(f64),
+    U2(f64, f64),
+    U3(f64, f64, f64),
+    Controlled {
+        controls: Vec<usize>,
+        target: Box<Gate>,
+    },
+}
+
+impl Gate {
+    fn apply(
+        &self,
+        state: &[Complex64],
+        targets: Vec<usize>,
+        num_qubits: usize,
+        parallel_threshold: usize,
+    ) -> Vec<Complex64> {
+        match self {
+            Gate::CNOT => apply_two_qubit_gate(state, cnot(), targets, num_qubits, parallel_threshold),
+            Gate::SWAP => apply_two_qubit_gate(state, swap(), targets, num_qubits, parallel_threshold),
+            Gate::Controlled { controls, target } => {
+                let matrix = target
+                    .single_qubit_matrix()
+                    .expect("Controlled gate target must be a single-qubit gate");
+                apply_controlled_gate(
+                    state,
+                    matrix,
+                    controls,
+                    targets[0],
+                    num_qubits,
+                    parallel_threshold,
+                )
+            }
+            _ => {
+                let matrix = self
+                    .single_qubit_matrix()
+                    .expect("unhandled single-qubit gate variant");
+                apply_single_qubit_gate(state, matrix, targets[0], num_qubits, parallel_threshold)
+            }
+        }
+    }
+
+    /// The 2x2 matrix for gates that act on a single qubit, or `None` for
+    /// gates (`CNOT`, `SWAP`, `Controlled`) that don't reduce to one.
+    fn single_qubit_matrix(&self) -> Option<[[Complex64; 2]; 2]> {
+        match self {
+            Gate::H => Some(hadamard()),
+            Gate::T => Some(t_gate()),
+            Gate::X => Some(pauli_x()),
+            Gate::Y => Some(pauli_y()),
+            Gate::Z => Some(pauli_z()),
+            Gate::ID => Some(identity()),
+            Gate::RX(theta) => Some(rx(*theta)),
+            Gate::RY(theta) => Some(ry(*theta)),
+            Gate::RZ(theta) => Some(rz(*theta)),
+            Gate::S => Some(s_gate()),
+            Gate::Sdg => Some(s_dagger()),
+            Gate::V => Some(v_gate()),
+            Gate::U1(lambda) => Some(u1(*lambda)),
+            Gate::U2(phi, lambda) => Some(u2(*phi, *lambda)),
+            Gate::U3(theta, phi, lambda) => Some(u3(*theta, *phi, *lambda)),
+            Gate::CNOT | Gate::SWAP | Gate::Controlled { .. } => None,
+        }
+    }
+}
+
+/// `e^{i*theta}` as a `Complex64`, used to build the phase gates below.
+fn cis(theta: f64) -> Complex64 {
+    Complex64::new(theta.cos(), theta.sin())
+}
+
+fn rx(theta: f64) -> [[Complex64; 2]; 2] {
+    let c = Complex64::new((theta / 2.0).cos(), 0.0);
+    let s = Complex64::new(0.0, -(theta / 2.0).sin());
+    [[c, s], [s, c]]
+}
+
+fn ry(theta: f64) -> [[Complex64; 2]; 2] {
+    let c = Complex64::new((theta / 2.0).cos(), 0.0);
+    let s = Complex64::new((theta / 2.0).sin(), 0.0);
+    [[c, -s], [s, c]]
+}
+
+fn rz(theta: f64) -> [[Complex64; 2]; 2] {
+    [
+        [cis(-theta / 2.0), Complex64::new(0.0, 0.0)],
+        [Complex64::new(0.0, 0.0), cis(theta / 2.0)],
+    ]
+}
+
+fn v_gate() -> [[Complex64; 2]; 2] {
+    let half = Complex64::new(0.5, 0.0);
+    let plus = Complex64::new(1.0, 1.0) * half;
+    let minus = Complex64::new(1.0, -1.0) * half;
+    [[plus, minus], [minus, plus]]
+}
+
+fn u1(lambda: f64) -> [[Complex64; 2]; 2] {
+    [
+        [Complex64::new(1.0, 0.0), Complex64::new(0.0, 0.0)],
+        [Complex64::new(0.0, 0.0), cis(lambda)],
+    ]
+}
+
+fn u2(phi: f64, lambda: f64) -> [[Complex64; 2]; 2] {
+    let scale = 1.0 / (2.0_f64).sqrt();
+    [
+        [Complex64::new(scale, 0.0), -cis(lambda) * scale],
+        [cis(phi) * scale, cis(phi + lambda) * scale],
+    ]
+}
+
+fn u3(theta: f64, phi: f64, lambda: f64) -> [[Complex64; 2]; 2] {
+    let c = Complex64::new((theta / 2.0).cos(), 0.0);
+    let s = Complex64::new((theta / 2.0).sin(), 0.0);
+    [
+        [c, -cis(lambda) * s],
+        [cis(phi) * s, cis(phi + lambda) * c],
+    ]
+}
+
+fn hadamard() -> [[Complex64; 2]; 2] {
+    let scale = 1.0 / (2.0_f64).sqrt();
+    [
+        [Complex64::new(scale, 0.0), Complex64::new(scale, 0.0)],
+        [Complex64::new(scale, 0.0), Complex64::new(-scale, 0.0)],
+    ]
+}
+
+fn t_gate() -> [[Complex64; 2]; 2] {
+    [
+        [Complex64::new(1.0, 0.0), Complex64::new(0.0, 0.0)],
+        [Complex64::new(0.0, 0.0), Complex64::new(0.7071, 0.7071)],
+    ]
+}
+
+fn pauli_x() -> [[Complex64; 2]; 2] {
+    [
+        [Complex64::new(0.0, 0.0), Complex64::new(1.0, 0.0)],
+        [Complex64::new(1.0, 0.0), Complex64::new(0.0, 0.0)],
+    ]
+}
+
+fn pauli_y() -> [[Complex64; 2]; 2] {
+    [
+        [Complex64::new(0.0, 0.0), Complex64::new(0.0, -1.0)],
+        [Complex64::new(0.0, 1.0), Complex64::new(0.0, 0.0)],
+    ]
+}
+
+fn pauli_z() -> [[Complex64; 2]; 2] {
+    [
+        [Complex64::new(1.0, 0.0), Complex64::new(0.0, 0.0)],
+        [Complex64::new(0.0, 0.0), Complex64::new(-1.0, 0.0)],
+    ]
+}
+
+fn identity() -> [[Complex64; 2]; 2] {
+    [
+        [Complex64::new(1.0, 0.0), Complex64::new(0.0, 0.0)],
+        [Complex64::new(0.0, 0.0), Complex64::new(1.0, 0.0)],
+    ]
+}
+
+/// `S = diag(1, i)`, used internally to rotate into/out of the Y basis for
+/// measurement, and as the matrix of `Gate::S`.
+fn s_gate() -> [[Complex64; 2]; 2] {
+    [
+        [Complex64::new(1.0, 0.0), Complex64::new(0.0, 0.0)],
+        [Complex64::new(0.0, 0.0), Complex64::new(0.0, 1.0)],
+    ]
+}
+
+fn s_dagger() -> [[Complex64; 2]; 2] {
+    [
+        [Complex64::new(1.0, 0.0), Complex64::new(0.0, 0.0)],
+        [Complex64::new(0.0, 0.0), Complex64::new(0.0, -1.0)],
+    ]
+}
+
+fn cnot() -> [[Complex64; 4]; 4] {
+    [
+        [
+            Complex64::new(1.0, 0.0),
+            Complex64::new(0.0, 0.0),
+            Complex64::new(0.0, 0.0),
+            Complex64::new(0.0, 0.0),
+        ],
+        [
+            Complex64::new(0.0, 0.0),
+            Complex64::new(1.0, 0.0),
+            Complex64::new(0.0, 0.0),
+            Complex64::new(0.0, 0.0),
+        ],
+        [
+            Complex64::new(0.0, 0.0),
+            Complex64::new(0.0, 0.0),
+            Complex64::new(0.0, 0.0),
+            Complex64::new(1.0, 0.0),
+        ],
+        [
+            Complex64::new(0.0, 0.0),
+            Complex64::new(0.0, 0.0),
+            Complex64::new(1.0, 0.0),
+            Complex64::new(0.0, 0.0),
+        ],
+    ]
+}
+
+fn swap() -> [[Complex64; 4]; 4] {
+    [
+        [
+            Complex64::new(1.0, 0.0),
+            Complex64::new(0.0, 0.0),
+            Complex64::new(0.0, 0.0),
+            Complex64::new(0.0, 0.0),
+        ],
+        [
+            Complex64::new(0.0, 0.0),
+            Complex64::new(0.0, 0.0),
+            Complex64::new(1.0, 0.0),
+            Complex64::new(0.0, 0.0),
+        ],
+        [
+            Complex64::new(0.0, 0.0),
+            Complex64::new(1.0, 0.0),
+            Complex64::new(0.0, 0.0),
+            Complex64::new(0.0, 0.0),
+        ],
+        [
+            Complex64::new(0.0, 0.0),
+            Complex64::new(0.0, 0.0),
+            Complex64::new(0.0, 0.0),
+            Complex64::new(1.0, 0.0),
+        ],
+    ]
+}
+
+fn apply_single_qubit_gate(
+    state: &[Complex64],
+    gate: [[Complex64; 2]; 2],
+    target: usize,
+    num_qubits: usize,
+    parallel_threshold: usize,
+) -> Vec<Complex64> {
+    let dim = 1 << num_qubits;
+    let mut new_state = vec![Complex64::new(0.0, 0.0); dim];
+
+    let amplitude_at = |i: usize| {
+        let target_bit = (i >> target) & 1;
+        let mut amp = Complex64::new(0.0, 0.0);
+        for j in 0..2 {
+            let source = (i & !(1 << target)) | (j << target);
+            amp += gate[target_bit][j] * state[source];
+        }
+        amp
+    };
+
+    if dim >= parallel_threshold {
+        new_state
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(i, amp)| *amp = amplitude_at(i));
+    } else {
+        for (i, amp) in new_state.iter_mut().enumerate() {
+            *amp = amplitude_at(i);
+        }
+    }
+
+    new_state
+}
+
+fn apply_two_qubit_gate(
+    state: &[Complex64],
+    gate: [[Complex64; 4]; 4],
+    targets: Vec<usize>,
+    num_qubits: usize,
+    parallel_threshold: usize,
+) -> Vec<Complex64> {
+    let dim = 1 << num_qubits;
+    let mut new_state = vec![Complex64::new(0.0, 0.0); dim];
+
+    let control = targets[0];
+    let target = targets[1];
+
+    let amplitude_at = |i: usize| {
+        let control_bit = (i >> control) & 1;
+        let target_bit = (i >> target) & 1;
+        let index = (control_bit << 1) | target_bit;
+
+        let mut amp = Complex64::new(0.0, 0.0);
+        for j in 0..4 {
+            let source = (i & !(1 << control) & !(1 << target))
+                | ((j >> 1) << control)
+                | ((j & 1) << target);
+            amp += gate[index][j] * state[source];
+        }
+        amp
+    };
+
+    if dim >= parallel_threshold {
+        new_state
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(i, amp)| *amp = amplitude_at(i));
+    } else {
+        for (i, amp) in new_state.iter_mut().enumerate() {
+            *amp = amplitude_at(i);
+        }
+    }
+
+    new_state
+}
+
+/// Apply a single-qubit `gate` to `target`, but only on basis states where
+/// every qubit in `controls` is 1; all other amplitudes pass through
+/// unchanged. Generalizes `CNOT`/`Toffoli`/`CZ`/controlled-rotations to one
+/// code path.
+fn apply_controlled_gate(
+    state: &[Complex64],
+    gate: [[Complex64; 2]; 2],
+    controls: &[usize],
+    target: usize,
+    num_qubits: usize,
+    parallel_threshold: usize,
+) -> Vec<Complex64> {
+    assert!(
+        target < num_qubits,
+        "controlled gate target {} out of range for {} qubits",
+        target,
+        num_qubits
+    );
+    assert!(
+        controls.iter().all(|&c| c < num_qubits),
+        "controlled gate controls {:?} out of range for {} qubits",
+        controls,
+        num_qubits
+    );
+
+    let dim = 1 << num_qubits;
+    let mut new_state = vec![Complex64::new(0.0, 0.0); dim];
+
+    let amplitude_at = |i: usize| {
+        if !controls.iter().all(|&c| (i >> c) & 1 == 1) {
+            return state[i];
+        }
+
+        let target_bit = (i >> target) & 1;
+        let mut amp = Complex64::new(0.0, 0.0);
+        for j in 0..2 {
+            let source = (i & !(1 << target)) | (j << target);
+            amp += gate[target_bit][j] * state[source];
+        }
+        amp
+    };
+
+    if dim >= parallel_threshold {
+        new_state
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(i, amp)| *amp = amplitude_at(i));
+    } else {
+        for (i, amp) in new_state.iter_mut().enumerate() {
+            *amp = amplitude_at(i);
+        }
+    }
+
+    new_state
+}
+
+/// Rotate `state` in place so that a Z-basis measurement of `qubit` is
+/// equivalent to measuring it in `basis`.
+fn rotate_to_z_basis(
+    state: &mut Vec<Complex64>,
+    qubit: usize,
+    basis: Basis,
+    num_qubits: usize,
+    parallel_threshold: usize,
+) {
+    match basis {
+        Basis::Z => {}
+        Basis::X => {
+            *state = apply_single_qubit_gate(state, hadamard(), qubit, num_qubits, parallel_threshold);
+        }
+        Basis::Y => {
+            *state = apply_single_qubit_gate(state, s_dagger(), qubit, num_qubits, parallel_threshold);
+            *state = apply_single_qubit_gate(state, hadamard(), qubit, num_qubits, parallel_threshold);
+        }
+    }
+}
+
+/// Inverse of `rotate_to_z_basis`.
+fn rotate_from_z_basis(
+    state: &mut Vec<Complex64>,
+    qubit: usize,
+    basis: Basis,
+    num_qubits: usize,
+    parallel_threshold: usize,
+) {
+    match basis {
+        Basis::Z => {}
+        Basis::X => {
+            *state = apply_single_qubit_gate(state, hadamard(), qubit, num_qubits, parallel_threshold);
+        }
+        Basis::Y => {
+            *state = apply_single_qubit_gate(state, hadamard(), qubit, num_qubits, parallel_threshold);
+            *state = apply_single_qubit_gate(state, s_gate(), qubit, num_qubits, parallel_threshold);
+        }
+    }
+}
+
+/// Collapse `state` in place onto a single Z-basis outcome for `qubit`,
+/// drawing from the Born rule, and renormalize the surviving amplitudes.
+fn collapse_z(state: &mut Vec<Complex64>, qubit: usize, rng: &mut impl Rng) -> u8 {
+    let dim = state.len();
+    let p0: f64 = (0..dim)
+        .filter(|i| (i >> qubit) & 1 == 0)
+        .map(|i| state[i].norm_sqr())
+        .sum();
+
+    let r: f64 = rng.gen();
+    let outcome: u8 = if r < p0 { 0 } else { 1 };
+    let p_outcome = if outcome == 0 { p0 } else { 1.0 - p0 };
+    let norm = p_outcome.sqrt();
+
+    for i in 0..dim {
+        let bit = ((i >> qubit) & 1) as u8;
+        if bit != outcome {
+            state[i] = Complex64::new(0.0, 0.0);
+        } else {
+            state[i] /= norm;
+        }
+    }
+
+    outcome
+}
+
+/// Measure `qubit` in `basis`, collapsing and renormalizing `state` in place.
+fn measure_qubit(
+    state: &mut Vec<Complex64>,
+    qubit: usize,
+    basis: Basis,
+    num_qubits: usize,
+    rng: &mut impl Rng,
+    parallel_threshold: usize,
+) -> u8 {
+    rotate_to_z_basis(state, qubit, basis, num_qubits, parallel_threshold);
+    let outcome = collapse_z(state, qubit, rng);
+    rotate_from_z_basis(state, qubit, basis, num_qubits, parallel_threshold);
+    outcome
+}
+
+/// Compute the probability that `qubit` would read 0 if measured in `basis`,
+/// without collapsing `state`.
+fn peek_qubit(
+    state: &[Complex64],
+    qubit: usize,
+    basis: Basis,
+    num_qubits: usize,
+    parallel_threshold: usize,
+) -> f64 {
+    let mut rotated = state.to_vec();
+    rotate_to_z_basis(&mut rotated, qubit, basis, num_qubits, parallel_threshold);
+    (0..rotated.len())
+        .filter(|i| (i >> qubit) & 1 == 0)
+        .map(|i| rotated[i].norm_sqr())
+        .sum()
+}
+
+/// Render a single `(Gate, targets)` entry as an OpenQASM 2.0 statement.
+fn gate_to_qasm(gate: &Gate, targets: &[usize]) -> String {
+    match gate {
+        Gate::H => format!("h q[{}];", targets[0]),
+        Gate::T => format!("t q[{}];", targets[0]),
+        Gate::X => format!("x q[{}];", targets[0]),
+        Gate::Y => format!("y q[{}];", targets[0]),
+        Gate::Z => format!("z q[{}];", targets[0]),
+        Gate::ID => format!("id q[{}];", targets[0]),
+        Gate::CNOT => format!("cx q[{}],q[{}];", targets[0], targets[1]),
+        Gate::SWAP => format!("swap q[{}],q[{}];", targets[0], targets[1]),
+        Gate::RX(theta) => format!("rx({}) q[{}];", theta, targets[0]),
+        Gate::RY(theta) => format!("ry({}) q[{}];", theta, targets[0]),
+        Gate::RZ(theta) => format!("rz({}) q[{}];", theta, targets[0]),
+        Gate::S => format!("s q[{}];", targets[0]),
+        Gate::Sdg => format!("sdg q[{}];", targets[0]),
+        Gate::V => format!("sx q[{}];", targets[0]),
+        Gate::U1(lambda) => format!("u1({}) q[{}];", lambda, targets[0]),
+        Gate::U2(phi, lambda) => format!("u2({},{}) q[{}];", phi, lambda, targets[0]),
+        Gate::U3(theta, phi, lambda) => {
+            format!("u3({},{},{}) q[{}];", theta, phi, lambda, targets[0])
+        }
+        Gate::Controlled { controls, target } => controlled_to_qasm(controls, target, targets[0]),
+    }
+}
+
+/// Render a `Gate::Controlled` as one of qelib1.inc's built-in controlled
+/// gates when possible, falling back to a comment for combinations that
+/// OpenQASM 2.0 has no standard gate for.
+fn controlled_to_qasm(controls: &[usize], target: &Gate, target_qubit: usize) -> String {
+    match (controls, target) {
+        ([c], Gate::X) => format!("cx q[{}],q[{}];", c, target_qubit),
+        ([c], Gate::Y) => format!("cy q[{}],q[{}];", c, target_qubit),
+        ([c], Gate::Z) => format!("cz q[{}],q[{}];", c, target_qubit),
+        ([c], Gate::H) => format!("ch q[{}],q[{}];", c, target_qubit),
+        ([c], Gate::RZ(theta)) => format!("crz({}) q[{}],q[{}];", theta, c, target_qubit),
+        ([c], Gate::U1(lambda)) => format!("cu1({}) q[{}],q[{}];", lambda, c, target_qubit),
+        ([c], Gate::U3(theta, phi, lambda)) => format!(
+            "cu3({},{},{}) q[{}],q[{}];",
+            theta, phi, lambda, c, target_qubit
+        ),
+        ([c1, c2], Gate::X) => format!("ccx q[{}],q[{}],q[{}];", c1, c2, target_qubit),
+        _ => format!(
+            "// unsupported controlled gate: {:?} controlled by {:?} -> q[{}]",
+            target, controls, target_qubit
+        ),
+    }
+}
+
+/// Inverse of `gate_to_qasm`/`controlled_to_qasm`: build a `(Gate, targets)`
+/// pair from a parsed gate name, its angle parameters, and its qubit list.
+/// `num_qubits` bounds-checks every index in `qubits` (including control
+/// qubits embedded in a resulting `Gate::Controlled`), since they all come
+/// from this same slice.
+fn gate_from_qasm(
+    name: &str,
+    params: &[f64],
+    qubits: &[usize],
+    num_qubits: usize,
+) -> Result<(Gate, Vec<usize>), String> {
+    let p = |i: usize| params.get(i).copied().unwrap_or(0.0);
+
+    for &q in qubits {
+        check_qubit_index(q, num_qubits)?;
+    }
+
+    let require = |arity: usize| -> Result<(), String> {
+        if qubits.len() != arity {
+            Err(format!(
+                "gate '{}' expects {} qubit(s), got {}: {:?}",
+                name,
+                arity,
+                qubits.len(),
+                qubits
+            ))
+        } else {
+            Ok(())
+        }
+    };
+
+    let (gate, targets) = match name {
+        "h" => {
+            require(1)?;
+            (Gate::H, vec![qubits[0]])
+        }
+        "t" => {
+            require(1)?;
+            (Gate::T, vec![qubits[0]])
+        }
+        "x" => {
+            require(1)?;
+            (Gate::X, vec![qubits[0]])
+        }
+        "y" => {
+            require(1)?;
+            (Gate::Y, vec![qubits[0]])
+        }
+        "z" => {
+            require(1)?;
+            (Gate::Z, vec![qubits[0]])
+        }
+        "id" => {
+            require(1)?;
+            (Gate::ID, vec![qubits[0]])
+        }
+        "swap" => {
+            require(2)?;
+            (Gate::SWAP, qubits.to_vec())
+        }
+        "cx" | "cnot" => {
+            require(2)?;
+            (Gate::CNOT, qubits.to_vec())
+        }
+        "rx" => {
+            require(1)?;
+            (Gate::RX(p(0)), vec![qubits[0]])
+        }
+        "ry" => {
+            require(1)?;
+            (Gate::RY(p(0)), vec![qubits[0]])
+        }
+        "rz" => {
+            require(1)?;
+            (Gate::RZ(p(0)), vec![qubits[0]])
+        }
+        "s" => {
+            require(1)?;
+            (Gate::S, vec![qubits[0]])
+        }
+        "sdg" => {
+            require(1)?;
+            (Gate::Sdg, vec![qubits[0]])
+        }
+        "sx" | "v" => {
+            require(1)?;
+            (Gate::V, vec![qubits[0]])
+        }
+        "u1" => {
+            require(1)?;
+            (Gate::U1(p(0)), vec![qubits[0]])
+        }
+        "u2" => {
+            require(1)?;
+            (Gate::U2(p(0), p(1)), vec![qubits[0]])
+        }
+        "u3" => {
+            require(1)?;
+            (Gate::U3(p(0), p(1), p(2)), vec![qubits[0]])
+        }
+        "cy" => {
+            require(2)?;
+            (
+                Gate::Controlled {
+                    controls: vec![qubits[0]],
+                    target: Box::new(Gate::Y),
+                },
+                vec![qubits[1]],
+            )
+        }
+        "cz" => {
+            require(2)?;
+            (
+                Gate::Controlled {
+                    controls: vec![qubits[0]],
+                    target: Box::new(Gate::Z),
+                },
+                vec![qubits[1]],
+            )
+        }
+        "ch" => {
+            require(2)?;
+            (
+                Gate::Controlled {
+                    controls: vec![qubits[0]],
+                    target: Box::new(Gate::H),
+                },
+                vec![qubits[1]],
+            )
+        }
+        "crz" => {
+            require(2)?;
+            (
+                Gate::Controlled {
+                    controls: vec![qubits[0]],
+                    target: Box::new(Gate::RZ(p(0))),
+                },
+                vec![qubits[1]],
+            )
+        }
+        "cu1" => {
+            require(2)?;
+            (
+                Gate::Controlled {
+                    controls: vec![qubits[0]],
+                    target: Box::new(Gate::U1(p(0))),
+                },
+                vec![qubits[1]],
+            )
+        }
+        "cu3" => {
+            require(2)?;
+            (
+                Gate::Controlled {
+                    controls: vec![qubits[0]],
+                    target: Box::new(Gate::U3(p(0), p(1), p(2))),
+                },
+                vec![qubits[1]],
+            )
+        }
+        "ccx" => {
+            require(3)?;
+            (
+                Gate::Controlled {
+                    controls: vec![qubits[0], qubits[1]],
+                    target: Box::new(Gate::X),
+                },
+                vec![qubits[2]],
+            )
+        }
+        _ => return Err(format!("unsupported OpenQASM gate: {}", name)),
+    };
+
+    Ok((gate, targets))
+}
+
+/// Strip the trailing `//` line comment, if any, from each line of `s`.
+fn strip_qasm_comment(s: &str) -> String {
+    s.lines()
+        .map(|line| match line.find("//") {
+            Some(idx) => &line[..idx],
+            None => line,
+        })
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+/// Parse `q[3]`-style register declarations (the part of `qreg q[3]` after
+/// the `qreg`/`creg` keyword has been stripped) into their size.
+fn parse_register_size(rest: &str, name: &str) -> Result<usize, String> {
+    let rest = rest.trim();
+    let inner = rest
+        .strip_prefix(name)
+        .ok_or_else(|| format!("expected register name '{}' in '{}'", name, rest))?
+        .trim()
+        .strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .ok_or_else(|| format!("malformed register declaration: {}", rest))?;
+    inner
+        .trim()
+        .parse::<usize>()
+        .map_err(|_| format!("invalid register size: {}", inner))
+}
+
+/// Parse a comma-separated list of `q[i]`-style qubit references.
+fn parse_qubit_list(token: &str) -> Result<Vec<usize>, String> {
+    token
+        .split(',')
+        .map(|part| {
+            part.trim()
+                .split(&['[', ']'][..])
+                .filter_map(|s| s.parse::<usize>().ok())
+                .next()
+                .ok_or_else(|| format!("invalid qubit reference: {}", part))
+        })
+        .collect()
+}
+
+/// Parse a single `q[i]`-style reference, e.g. for an instruction slot that
+/// names exactly one qubit or classical bit.
+fn parse_single_qubit(token: &str) -> Result<usize, String> {
+    parse_qubit_list(token)?
+        .first()
+        .copied()
+        .ok_or_else(|| format!("invalid qubit reference: {}", token))
+}
+
+/// Parse the `q[0] -> c[0]` tail of a `measure` statement.
+fn parse_measure(rest: &str) -> Result<(usize, usize), String> {
+    let mut parts = rest.split("->");
+    let qubit_part = parts
+        .next()
+        .ok_or_else(|| "malformed measure statement".to_string())?;
+    let classical_part = parts
+        .next()
+        .ok_or_else(|| "malformed measure statement: missing '->'".to_string())?;
+
+    let qubit = *parse_qubit_list(qubit_part)?
+        .first()
+        .ok_or_else(|| "malformed measure statement: missing qubit".to_string())?;
+    let classical_bit = *parse_qubit_list(classical_part)?
+        .first()
+        .ok_or_else(|| "malformed measure statement: missing classical bit".to_string())?;
+
+    Ok((qubit, classical_bit))
+}
+
+/// Parse the `(c==1) x q[0]` tail of an `if` statement into the classical
+/// bits the condition reads (the whole `num_clbits`-wide register, bit `i`
+/// contributing `2^i`), the value to compare against, and the gate
+/// statement to run when it matches.
+fn parse_if_statement(rest: &str, num_clbits: usize) -> Result<(Vec<usize>, u64, String), String> {
+    let rest = rest.trim();
+    let close = rest
+        .find(')')
+        .ok_or_else(|| format!("malformed if statement: {}", rest))?;
+    let condition = rest
+        .strip_prefix('(')
+        .ok_or_else(|| format!("malformed if statement: {}", rest))?
+        .get(..close - 1)
+        .ok_or_else(|| format!("malformed if statement: {}", rest))?;
+    let gate_stmt = rest[close + 1..].trim().to_string();
+
+    let mut parts = condition.splitn(2, "==");
+    parts
+        .next()
+        .ok_or_else(|| format!("malformed if condition: {}", condition))?;
+    let value: u64 = parts
+        .next()
+        .ok_or_else(|| format!("malformed if condition: {}", condition))?
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid condition value: {}", condition))?;
+
+    Ok(((0..num_clbits).collect(), value, gate_stmt))
+}
+
+/// Check that `qubit` names an existing wire in a `num_qubits`-wide register.
+fn check_qubit_index(qubit: usize, num_qubits: usize) -> Result<(), String> {
+    if qubit >= num_qubits {
+        Err(format!(
+            "qubit index {} out of range for {}-qubit register",
+            qubit, num_qubits
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that `bit` names an existing slot in a `num_clbits`-wide register.
+fn check_clbit_index(bit: usize, num_clbits: usize) -> Result<(), String> {
+    if bit >= num_clbits {
+        Err(format!(
+            "classical bit index {} out of range for {}-bit register",
+            bit, num_clbits
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Split an instruction token like `"rx(1.5708)"` into its gate name and the
+/// parenthesized, comma-separated list of float arguments, e.g.
+/// `("rx", vec![1.5708])`. Tokens with no parentheses yield an empty list.
+/// Returns `Err` if any comma-separated argument fails to parse as a float,
+/// rather than silently dropping it (and quietly defaulting its gate's
+/// angle to 0.0).
+fn split_gate_params(token: &str) -> Result<(&str, Vec<f64>), String> {
+    match token.find('(') {
+        Some(open) if token.ends_with(')') => {
+            let name = &token[..open];
+            let args = &token[open + 1..token.len() - 1];
+            let params = args
+                .split(',')
+                .map(|s| {
+                    s.trim()
+                        .parse::<f64>()
+                        .map_err(|_| format!("invalid gate parameter '{}' in '{}'", s.trim(), token))
+                })
+                .collect::<Result<Vec<f64>, String>>()?;
+            Ok((name, params))
+        }
+        _ => Ok((token, Vec::new())),
+    }
+}
+
+/// Parse a single measurement-basis token (`"x"`/`"y"`/`"z"`, case-insensitive).
+fn parse_basis(token: &str) -> Result<Basis, String> {
+    match token.to_ascii_lowercase().as_str() {
+        "x" => Ok(Basis::X),
+        "y" => Ok(Basis::Y),
+        "z" => Ok(Basis::Z),
+        _ => Err(format!("invalid measurement basis: {}", token)),
+    }
+}
+
+/// Dispatch a single whitespace-split instruction (everything but a plain
+/// unitary gate application, which is handled by the caller) onto the
+/// matching `Circuit` method. Returns `Ok(true)` if `instruction` was one of
+/// these special forms, `Ok(false)` if the caller should fall back to
+/// treating it as a gate, or `Err` if it matched a special form but was
+/// malformed.
+fn apply_special_instruction(
+    circuit: &mut Circuit,
+    instruction: &str,
+    parts: &[&str],
+) -> Result<bool, String> {
+    if parts[0] == "if" || parts[0].starts_with("if(") {
+        let rest = instruction
+            .strip_prefix("if")
+            .expect("parts[0] starting with \"if\" implies this prefix is present");
+        let (classical_bits, value, gate_stmt) = parse_if_statement(rest, circuit.num_clbits)?;
+        let gate_parts: Vec<&str> = gate_stmt.split_whitespace().collect();
+        if gate_parts.len() < 2 {
+            return Err(format!("malformed conditional gate statement: {}", instruction));
+        }
+        let (name, params) = split_gate_params(gate_parts[0])?;
+        let qubits = parse_qubit_list(gate_parts[1])?;
+        let (gate, targets) = gate_from_qasm(name, &params, &qubits, circuit.num_qubits)?;
+        circuit.conditional_gate(classical_bits, value, gate, targets);
+        return Ok(true);
+    }
+
+    match parts[0] {
+        "measure" => {
+            if parts.len() != 4 {
+                return Err(format!(
+                    "malformed measure instruction (want 'measure q[i] <x|y|z> c[j]'): {}",
+                    instruction
+                ));
+            }
+            let qubit = parse_single_qubit(parts[1])?;
+            let basis = parse_basis(parts[2])?;
+            let classical_bit = parse_single_qubit(parts[3])?;
+            check_qubit_index(qubit, circuit.num_qubits)?;
+            check_clbit_index(classical_bit, circuit.num_clbits)?;
+            circuit.measure(qubit, basis, classical_bit);
+            Ok(true)
+        }
+        "measureall" => {
+            if parts.len() != 2 {
+                return Err(format!(
+                    "malformed measureall instruction (want 'measureall <x|y|z>'): {}",
+                    instruction
+                ));
+            }
+            circuit.measure_all(parse_basis(parts[1])?);
+            Ok(true)
+        }
+        "peek" => {
+            if parts.len() != 4 {
+                return Err(format!(
+                    "malformed peek instruction (want 'peek q[i] <x|y|z> p[j]'): {}",
+                    instruction
+                ));
+            }
+            let qubit = parse_single_qubit(parts[1])?;
+            let basis = parse_basis(parts[2])?;
+            let peek_slot = parse_single_qubit(parts[3])?;
+            check_qubit_index(qubit, circuit.num_qubits)?;
+            circuit.peek(qubit, basis, peek_slot);
+            Ok(true)
+        }
+        "reset" => {
+            if parts.len() != 2 {
+                return Err(format!(
+                    "malformed reset instruction (want 'reset q[i]'): {}",
+                    instruction
+                ));
+            }
+            let qubit = parse_single_qubit(parts[1])?;
+            check_qubit_index(qubit, circuit.num_qubits)?;
+            circuit.reset(qubit);
+            Ok(true)
+        }
+        "resetall" => {
+            circuit.reset_all();
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+fn prompt(message: &str) -> String {
+    print!("{}", message);
+    io::stdout().flush().unwrap();
+    let mut input = String::new();
+    io::stdin()
+        .read_line(&mut input)
+        .expect("Failed to read line");
+    input.trim().to_string()
+}
+
+fn main() {
+    let num_qubits: usize = prompt("Enter number of qubits: ")
+        .parse()
+        .expect("Invalid number");
+
+    let mut circuit = Circuit::new(num_qubits);
+
+    let threshold_input = prompt("Enter parallel gate-application threshold (blank for default): ");
+    if let Ok(threshold) = threshold_input.parse::<usize>() {
+        circuit.set_parallel_threshold(threshold);
+    }
+
+    let num_instructions: usize = prompt("Enter number of instructions: ")
+        .parse()
+        .expect("Invalid number");
+
+    for _ in 0..num_instructions {
+        let instruction = prompt(
+            "Enter instruction (e.g. 'h q[0]', 'cx q[0],q[1]', 'measure q[0] z c[0]', \
+             'reset q[0]', 'if(c==1) x q[0]'): ",
+        );
+
+        let parts: Vec<&str> = instruction.split_whitespace().collect();
+        if parts.is_empty() {
+            println!("Invalid instruction: {}", instruction);
+            continue;
+        }
+
+        match apply_special_instruction(&mut circuit, &instruction, &parts) {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        }
+
+        if parts.len() < 2 {
+            println!("Invalid instruction: {}", instruction);
+            continue;
+        }
+
+        let (name, params) = match split_gate_params(parts[0]) {
+            Ok(result) => result,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
+        let qubits = match parse_qubit_list(parts[1]) {
+            Ok(qubits) => qubits,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
+
+        let (gate, targets) = match gate_from_qasm(name, &params, &qubits, num_qubits) {
+            Ok(result) => result,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
+
+        circuit.add_gate(gate, targets);
+    }
+
+    println!("Starting circuit execution...");
+    let (final_state, classical, peeks) = circuit.run_with_rng(&mut rand::thread_rng());
+
+    let probabilities = circuit.compute_probabilities(&final_state);
+    println!("Final probabilities:");
+    for (state, prob) in probabilities.iter().enumerate() {
+        println!(
+            "State |{:0width$b}>: {:.5}",
+            state,
+            prob,
+            width = num_qubits
+        );
+    }
+
+    if !classical.is_empty() {
+        let bitstring: String = classical
+            .iter()
+            .map(|bit| if *bit == 1 { '1' } else { '0' })
+            .collect();
+        println!("Classical register: {}", bitstring);
+    }
+    let mut peek_slots: Vec<&usize> = peeks.keys().collect();
+    peek_slots.sort();
+    for slot in peek_slots {
+        println!("Peek register p[{}]: P(0) = {:.5}", slot, peeks[slot]);
+    }
+
+    let shots: usize = prompt("Enter number of shots to sample (0 to skip): ")
+        .parse()
+        .unwrap_or(0);
+    if shots > 0 {
+        println!("Sampling {} shots:", shots);
+        let mut counts: Vec<(String, usize)> = circuit.sample(shots).into_iter().collect();
+        counts.sort();
+        for (bitstring, count) in counts {
+            println!("  {}: {}", bitstring, count);
+        }
+    }
+
+    let qasm = circuit.to_openqasm();
+    println!("OpenQASM 2.0 export:\n{}", qasm);
+    match Circuit::from_openqasm(&qasm) {
+        Ok(roundtripped) => println!(
+            "Round-tripped circuit via OpenQASM: {} qubits, {} ops",
+            roundtripped.num_qubits,
+            roundtripped.gates.len()
+        ),
+        Err(e) => println!("Failed to round-trip circuit through OpenQASM: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// A Bell pair should only ever collapse to |00> or |11>, never the
+    /// mixed outcomes, and roughly half-and-half over enough shots.
+    #[test]
+    fn bell_state_collapses_to_correlated_outcomes() {
+        let mut circuit = Circuit::new(2);
+        circuit.add_gate(Gate::H, vec![0]);
+        circuit.add_gate(Gate::CNOT, vec![0, 1]);
+        circuit.measure_all(Basis::Z);
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut zeros = 0;
+        let mut ones = 0;
+        for _ in 0..500 {
+            let (_, classical, _) = circuit.run_with_rng(&mut rng);
+            assert_eq!(
+                classical[0], classical[1],
+                "Bell pair measured uncorrelated outcome: {:?}",
+                classical
+            );
+            if classical[0] == 0 {
+                zeros += 1;
+            } else {
+                ones += 1;
+            }
+        }
+        assert!(zeros > 150 && ones > 150, "expected a roughly even split, got {}/{}", zeros, ones);
+    }
+
+    /// Exporting and reimporting a circuit through OpenQASM should preserve
+    /// its op count, including a classically-conditioned gate over the
+    /// full classical register.
+    #[test]
+    fn qasm_round_trip_preserves_ops() {
+        let mut circuit = Circuit::new(2);
+        circuit.add_gate(Gate::X, vec![0]);
+        circuit.measure(0, Basis::Z, 0);
+        circuit.measure(1, Basis::Z, 1);
+        circuit.conditional_gate(vec![0, 1], 1, Gate::X, vec![1]);
+
+        let qasm = circuit.to_openqasm();
+        let roundtripped = Circuit::from_openqasm(&qasm).expect("round-trip should parse");
+
+        assert_eq!(roundtripped.num_qubits, circuit.num_qubits);
+        assert_eq!(roundtripped.gates.len(), circuit.gates.len());
+        assert!(
+            matches!(roundtripped.gates.last(), Some(CircuitOp::ConditionalGate { .. })),
+            "conditional gate should round-trip as a real `if`, not a dropped comment"
+        );
+    }
+
+    /// An out-of-range classical bit index in a QASM `measure` statement
+    /// should be a parse error, not a panic in `run_with_rng`.
+    #[test]
+    fn out_of_range_measure_target_is_an_error() {
+        let qasm = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[2];\ncreg c[2];\nmeasure q[0] -> c[5];\n";
+        assert!(Circuit::from_openqasm(qasm).is_err());
+    }
+}